@@ -6,12 +6,40 @@ use core::prelude::*;
 use super::page;
 use core::cmp;
 use core::mem::{size_of, transmute};
-use core::ptr::{write_bytes, write};
+use core::ptr::{write_bytes, write, copy_nonoverlapping};
 use core::fmt;
 
 const FREE_FILL : u8 = 0xF7;
 const ALOC_FILL : u8 = 0x7F;
 
+/// Names the invariant a `deallocate_checked` call found broken, so the `dbg!`/`kpanic!` output
+/// can point at exactly what went wrong.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CheckMsg {
+    /// The tag at `ptr` is already free: either a double free, or a free of an interior/unowned
+    /// pointer that doesn't start a block.
+    DoubleFree,
+    /// `ptr` doesn't fall within this allocator's arena at all.
+    NotInArena,
+    /// `ptr` isn't aligned to `size_of::<Tag>()`, so it can't be a block start.
+    UnalignedPointer,
+    /// The free block following the one being freed no longer holds the `FREE_FILL` pattern
+    /// throughout its unlinked payload, implying something wrote into it after it was freed.
+    CorruptedFreeBlock,
+}
+
+impl fmt::Debug for CheckMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            CheckMsg::DoubleFree         => "DoubleFree",
+            CheckMsg::NotInArena         => "NotInArena",
+            CheckMsg::UnalignedPointer   => "UnalignedPointer",
+            CheckMsg::CorruptedFreeBlock => "CorruptedFreeBlock",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 /// This is a free list allocator. It allocates in two ways. A best fit allocator from the front
 /// for small objects and a best fit allocator from the back for > PAGE_SIZE objects. It does this
 /// to try to prevent fragmentation. This is implemented as an extreemly simple free list
@@ -23,9 +51,25 @@ pub struct BackupAllocator {
     pages           : usize,
     largest_space   : usize, // The largest continuous page aligned space in number of pages
     threshold_pages : usize, // The size below which we will consider space low in pages.
-    //next_allocator  : *mut BackupAllocator,
+    reserved_pages  : usize, // Pages pinned by `reserve` and not yet `release`d; see is_memory_low.
+    // Next link in the chain, lazily allocated once this one is exhausted.
+    next_allocator  : *mut BackupAllocator,
+    // Heads of the segregated free lists, bucketed by size class (see `class_for_units`). A
+    // block is linked into its class using the next/prev pointers stored in its own (otherwise
+    // unused) payload, so pushing/popping is O(1) instead of the O(n) full-chain walk that
+    // `allocate_small_linear` does. `do_recalculate` still walks the whole chain to coalesce.
+    small_free_lists : [*mut Tag; NUM_SMALL_CLASSES],
 }
 
+/// Number of size classes used for the small-object free lists, doubling from one `Tag` unit up
+/// to `1 << (NUM_SMALL_CLASSES - 1)` units. Generously sized so it never needs to track
+/// `page::SIZE` itself; unused high classes just stay empty.
+const NUM_SMALL_CLASSES : usize = 32;
+
+/// A free block too small to hold the intrusive next/prev pointers cannot be tracked in a size
+/// class; it's still found by the linear fallback scan.
+const MIN_LIST_NODE_UNITS : usize = 2;
+
 const DEFAULT_BACKUP_PAGES : usize = 128;
 
 #[cfg(not(TEST_LOW_MEMORY))]
@@ -39,9 +83,23 @@ pub const DEFAULT_BACKUP_ALLOCATOR : BackupAllocator = BackupAllocator {
     pages           : 0,
     largest_space   : 0,
     threshold_pages : 0,
-    //next_allocator  : 0 as *mut BackupAllocator,
+    reserved_pages  : 0,
+    next_allocator  : 0 as *mut BackupAllocator,
+    small_free_lists : [0 as *mut Tag; NUM_SMALL_CLASSES],
 };
 
+/// A span of the arena carved out by `reserve` and pinned against normal allocation until it is
+/// handed back with `release`.
+pub struct Reservation {
+    ptr   : *mut u8,
+    pages : usize,
+}
+
+impl Reservation {
+    pub fn as_ptr(&self) -> *mut u8 { self.ptr }
+    pub fn pages(&self) -> usize { self.pages }
+}
+
 /// Number of pages it would take to hold that many bytes.
 #[inline] fn pg_size(u: usize) -> usize { unsafe { page::addr_to_num(page::const_align_up(u as *const u8)) } }
 
@@ -70,6 +128,15 @@ impl Tag {
 
     pub fn next(&self) -> *mut Tag { unsafe { transmute(self.get_start().offset(self.size() as isize)) } }
 
+    // A free block's payload is unused, so the segregated free lists thread their intrusive
+    // next/prev pointers through the first two `usize`s of it. Only ever valid to read/write on
+    // a free block with at least `MIN_LIST_NODE_UNITS` units of payload.
+    fn links(&self) -> *mut *mut Tag { self.get_start() as *mut *mut Tag }
+    pub fn get_free_next(&self) -> *mut Tag { unsafe { *self.links() } }
+    pub fn get_free_prev(&self) -> *mut Tag { unsafe { *self.links().offset(1) } }
+    pub fn set_free_next(&mut self, t: *mut Tag) { unsafe { *self.links() = t; } }
+    pub fn set_free_prev(&mut self, t: *mut Tag) { unsafe { *self.links().offset(1) = t; } }
+
     pub fn get_page_aligned_part(&self, requested_pages: usize) -> Option<(*mut Tag, *mut Tag)> {
         // mem is CTAG........[:::::::::::::::::::::::::::::::::::::::::]....CTAG => GOOD
         // mem is         CTAG[:::::::::::::::::::::::::::::::::::::::::]....CTAG => GOOD
@@ -109,6 +176,9 @@ impl BackupAllocator {
             pages : size,
             largest_space : size - 1,
             threshold_pages : threshold,
+            reserved_pages : 0,
+            next_allocator : 0 as *mut BackupAllocator,
+            small_free_lists : [0 as *mut Tag; NUM_SMALL_CLASSES],
         };
         ret.setup();
         ret
@@ -120,45 +190,217 @@ impl BackupAllocator {
     }
 
     pub fn allocate(&self, size: usize, align: usize) -> *mut u8 {
+        self.allocate_with_size(size, align).0
+    }
+
+    /// Like `allocate`, but also reports the actual usable size of the returned block, which may
+    /// be larger than `size` (e.g. `allocate_small` avoiding a zero-length split tag, or
+    /// `allocate_pages` rounding up to page granularity).
+    pub fn allocate_with_size(&self, size: usize, align: usize) -> (*mut u8, usize) {
+        self.real_allocate_with_size(size, align, ALOC_FILL)
+    }
+
+    /// Like `allocate`, but writes zeros instead of the `ALOC_FILL` debug pattern, so kernel
+    /// subsystems that need cleared memory (e.g. page tables) don't have to zero it themselves.
+    pub fn allocate_zeroed(&self, size: usize, align: usize) -> *mut u8 {
+        self.allocate_zeroed_with_size(size, align).0
+    }
+
+    /// Combines `allocate_zeroed` and `allocate_with_size`.
+    pub fn allocate_zeroed_with_size(&self, size: usize, align: usize) -> (*mut u8, usize) {
+        self.real_allocate_with_size(size, align, 0)
+    }
+
+    fn real_allocate_with_size(&self, size: usize, align: usize, fill: u8) -> (*mut u8, usize) {
         // Force everything to be aligned by size_of::<Tag>.
         let req = (size + (size_of::<Tag>() - 1)) & (!(size_of::<Tag>() - 1));
         let res = self.real_allocate(req, align);
         unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).recalculate() };
         if !res.is_null() {
-            unsafe { write_bytes(res, ALOC_FILL, size); }
             let recieved_size =  unsafe { (res as *const Tag).offset(-1).as_ref().expect("shouldn't be null").size() };
+            // Fill the whole usable block, not just the requested `size`, so a caller using
+            // `allocate_zeroed_with_size` to grab the reported capacity gets it fully zeroed.
+            unsafe { write_bytes(res, fill, recieved_size); }
             dbg!(debug::MM|debug::BACKUP_MM, "allocated {:p}-{:p} which is {} bytes long for request for {}",
                  res, unsafe { res.offset(recieved_size as isize) }, recieved_size, size);
             if self.is_memory_low() {
                 dbg!(debug::MM|debug::DANGER, "We are currently low on memory! Largest space is {}", self.largest_space);
             }
+            (res, recieved_size)
         } else {
             dbg!(debug::MM|debug::BACKUP_MM, "unable to allocate {} bytes from backup", size);
+            (res, 0)
         }
-        res
     }
-    fn real_allocate(&self, size: usize, _align: usize) -> *mut u8 {
+    fn real_allocate(&self, size: usize, align: usize) -> *mut u8 {
         assert!((size % size_of::<Tag>()) == 0, "size of {} is not aligned to {}", size, size_of::<Tag>());
-        if pg_size(size) > self.largest_space + 1 {
-            dbg!(debug::MM|debug::DANGER, "Unable to allocate {} bytes from backup memory allocator!", size);
+        let res = if pg_size(size) > self.largest_space + 1 {
             0 as *mut u8
         } else if size >= page::SIZE {
             self.allocate_pages(pg_size(size))
         } else {
             self.allocate_small(size)
+        };
+        if !res.is_null() {
+            res
+        } else {
+            // This link can't satisfy the request (too little space, or no suitable segment);
+            // chain to (lazily creating, if needed) the next link rather than failing outright.
+            dbg!(debug::MM|debug::DANGER, "Unable to allocate {} bytes from this backup allocator link, chaining", size);
+            let next = self.ensure_next_allocator(size);
+            unsafe { (*next).real_allocate(size, align) }
+        }
+    }
+
+    /// Returns the next link in the chain, lazily creating one sized to cover at least
+    /// `min_size` bytes (rounded up to whole pages, same as any other page-granularity request,
+    /// plus one page for the link's own leading tag) if it doesn't exist yet.
+    fn ensure_next_allocator(&self, min_size: usize) -> *mut BackupAllocator {
+        if self.next_allocator.is_null() {
+            let pages = cmp::max(pg_size(min_size) + 1, DEFAULT_BACKUP_PAGES);
+            let mem = unsafe {
+                page::alloc_n(1).unwrap_or_else(|_| { kpanic!("Unable to allocate space for a chained backup allocator"); })
+            } as *mut BackupAllocator;
+            unsafe { write(mem, BackupAllocator::new(pages, self.threshold_pages)); }
+            dbg!(debug::MM|debug::BACKUP_MM, "chained a new {} page backup allocator at {:p}", pages, mem);
+            unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).next_allocator = mem; }
         }
+        self.next_allocator
     }
+    /// Size class holding blocks of at least `1 << class` units (a unit is `size_of::<Tag>()`
+    /// bytes). The starting class for a request may still hold smaller blocks (needs a fit
+    /// check), but every class above it is guaranteed big enough to pop blindly.
+    #[inline]
+    fn class_for_units(units: usize) -> usize {
+        if units < MIN_LIST_NODE_UNITS {
+            return 0;
+        }
+        let bits = size_of::<usize>() * 8;
+        cmp::min(bits - 1 - (units.leading_zeros() as usize), NUM_SMALL_CLASSES - 1)
+    }
+
+    /// Links a known-free block of `size` bytes onto the head of its size class's free list.
+    fn push_free(&self, t: *mut Tag, size: usize) {
+        let units = size / size_of::<Tag>();
+        if units < MIN_LIST_NODE_UNITS {
+            return;
+        }
+        let class = Self::class_for_units(units);
+        let old_head = self.small_free_lists[class];
+        {
+            let tag = unsafe { t.as_mut().expect("not null") };
+            tag.set_free_next(old_head);
+            tag.set_free_prev(0 as *mut Tag);
+        }
+        if let Some(head) = self.read_tag(old_head) {
+            head.set_free_prev(t);
+        }
+        unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).small_free_lists[class] = t; }
+    }
+
+    /// Unlinks a known-free block of `size` bytes from its size class's free list. A no-op for
+    /// blocks too small to have been tracked in the first place.
+    fn unlink_free(&self, t: *mut Tag, size: usize) {
+        let units = size / size_of::<Tag>();
+        if units < MIN_LIST_NODE_UNITS {
+            return;
+        }
+        let class = Self::class_for_units(units);
+        let (next, prev) = {
+            let tag = unsafe { t.as_mut().expect("not null") };
+            (tag.get_free_next(), tag.get_free_prev())
+        };
+        if let Some(p) = self.read_tag(prev) {
+            p.set_free_next(next);
+        } else {
+            unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).small_free_lists[class] = next; }
+        }
+        if let Some(n) = self.read_tag(next) {
+            n.set_free_prev(prev);
+        }
+    }
+
+    /// First-fit scan of a single size class's free list, unlinking and returning the first
+    /// block big enough for `req`.
+    fn find_fit_in_class(&self, class: usize, req: usize) -> Option<*mut Tag> {
+        let mut cur = self.small_free_lists[class];
+        loop {
+            let (size, next) = match self.read_tag(cur) {
+                Some(tag) => (tag.size(), tag.get_free_next()),
+                None => return None,
+            };
+            if size >= req {
+                self.unlink_free(cur, size);
+                return Some(cur);
+            }
+            cur = next;
+        }
+    }
+
+    /// Pops the head of a size class's free list, which is guaranteed to fit any request that
+    /// started searching below this class.
+    fn pop_head(&self, class: usize) -> Option<*mut Tag> {
+        let head = self.small_free_lists[class];
+        match self.read_tag(head) {
+            Some(tag) => {
+                let size = tag.size();
+                self.unlink_free(head, size);
+                Some(head)
+            },
+            None => None,
+        }
+    }
+
+    /// Marks `t` allocated for `req` bytes, splitting off and relisting the remainder (unless
+    /// it's too small to be worth its own tag).
+    fn carve(&self, t: *mut Tag, req: usize) -> *mut u8 {
+        let tag = unsafe { t.as_mut().expect("not null") };
+        let old_size = tag.size();
+        if old_size == req || old_size == req + size_of::<Tag>() {
+            // Size is an exact match, or close enough that the next split tag would be 0 length,
+            // which is good enough. Nothing should break with 0 length tags but we might as well
+            // avoid them on principle.
+            tag.set_allocated();
+            return tag.get_start();
+        }
+        let remaining_size = old_size - size_of::<Tag>() - req;
+        tag.set_size(req);
+        tag.set_allocated();
+        if let Some(new_tag) = self.read_tag(tag.next()) {
+            *new_tag = Tag::new(remaining_size);
+            let new_ptr = new_tag.get_tag_ptr();
+            unsafe { write_bytes(new_tag.get_start(), FREE_FILL, remaining_size); }
+            self.push_free(new_ptr, remaining_size);
+        }
+        tag.get_start()
+    }
+
     fn allocate_small(&self, req: usize) -> *mut u8 {
-        // Make size be even.
+        let units = req / size_of::<Tag>();
+        let start_class = Self::class_for_units(units);
+        if let Some(t) = self.find_fit_in_class(start_class, req) {
+            return self.carve(t, req);
+        }
+        for class in (start_class + 1)..NUM_SMALL_CLASSES {
+            if let Some(t) = self.pop_head(class) {
+                return self.carve(t, req);
+            }
+        }
+        // Classes are empty (free space may still sit in blocks the lists don't track: the
+        // never-split initial arena block, or blocks under `MIN_LIST_NODE_UNITS`); fall back to
+        // the full best-fit scan.
+        self.allocate_small_linear(req)
+    }
+
+    fn allocate_small_linear(&self, req: usize) -> *mut u8 {
         let mut best : Option<*mut Tag> = None;
         let mut c = self.read_tag(self.buf as *mut Tag);
         while c.is_some() {
             let cur = c.expect("Isn't null");
             if cur.is_free() && cur.size() >= req {
                 if cur.size() == req || cur.size() == req + size_of::<Tag>() {
-                    // Size is an exact match, or close enough that the next split tag would be 0
-                    // length, which is good enough. Nothing should break with 0 lenth tags but we
-                    // might as well avoid them on principle.
+                    let size = cur.size();
+                    self.unlink_free(cur.get_tag_ptr(), size);
                     cur.set_allocated();
                     return cur.get_start();
                 } else if best.clone().map(|t| { unsafe { t.as_mut().expect("not null").size() } }).unwrap_or(::core::usize::MAX) > req {
@@ -169,15 +411,9 @@ impl BackupAllocator {
         }
         match best {
             Some(t) => {
-                let tag = unsafe { t.as_mut().expect("not null") };
-                let old_size = tag.size();
-                let remaining_size = old_size - size_of::<Tag>() - req;
-                tag.set_size(req);
-                tag.set_allocated();
-                if let Some(new_tag) = self.read_tag(tag.next()) {
-                    *new_tag = Tag::new(remaining_size);
-                }
-                tag.get_start()
+                let old_size = unsafe { t.as_ref().expect("not null").size() };
+                self.unlink_free(t, old_size);
+                self.carve(t, req)
             },
             None => {
                 dbg!(debug::MM|debug::DANGER, "Unable to allocate {} bytes from backup memory allocator!. No suitable segments", req);
@@ -198,6 +434,8 @@ impl BackupAllocator {
             c = self.read_tag(cur.next());
         }
         if let Some((tag, (split_low, split_hi))) = best {
+            let old_size = unsafe { tag.as_ref().expect("not null").size() };
+            self.unlink_free(tag, old_size);
             let t = unsafe { tag.as_mut().expect("not null") };
             if t.get_tag_ptr() == split_low && t.next() == split_hi {
                 bassert!(pg_size(t.size()) == pgs);
@@ -212,10 +450,14 @@ impl BackupAllocator {
                     if let Some(end) = self.read_tag(split_hi) {
                         end.set_size(new_end_size);
                         end.set_free();
+                        unsafe { write_bytes(end.get_start(), FREE_FILL, new_end_size); }
+                        self.push_free(split_hi, new_end_size);
                     }
                 }
                 t.set_size(new_start_size);
                 t.set_free();
+                unsafe { write_bytes(t.get_start(), FREE_FILL, new_start_size); }
+                self.push_free(t.get_tag_ptr(), new_start_size);
                 let start = self.read_tag(split_low).expect("should never be null");
                 start.set_size(unsafe { page::num_to_addr::<u8>(pgs) as usize });
                 start.set_allocated();
@@ -227,10 +469,22 @@ impl BackupAllocator {
         }
     }
 
+    /// `size` may be either the size originally requested from `allocate`/`allocate_with_size` or
+    /// the usable size that `allocate_with_size` reported back; `deallocate_small`'s assertion
+    /// already tolerates both (the tag-rounded request, or that plus a trailing zero-length tag).
     pub fn deallocate(&self, ptr: *mut u8, size: usize, align: usize) {
-        unsafe { write_bytes(ptr, FREE_FILL, size); }
-        dbg!(debug::MM|debug::BACKUP_MM, "Request to deallocate {:p} of size {}", ptr, size);
+        if !self.contains_local(ptr) {
+            assert!(!self.next_allocator.is_null(), "{:p} does not belong to any link of this backup allocator", ptr);
+            return unsafe { (*self.next_allocator).deallocate(ptr, size, align) };
+        }
         let req = (size + (size_of::<Tag>() - 1)) & (!(size_of::<Tag>() - 1));
+        // Fill the tag's actual occupied size, not the raw request: `carve` sometimes leaves up
+        // to `size_of::<Tag>()` of slack past `req`, and that slack needs FREE_FILL too or
+        // `fill_intact` sees stale ALOC_FILL and flags a healthy block as corrupted.
+        let actual_size = self.read_tag(unsafe { (ptr as *mut Tag).offset(-1) })
+            .expect("contains_local() already checked ptr is within the arena").size();
+        unsafe { write_bytes(ptr, FREE_FILL, actual_size); }
+        dbg!(debug::MM|debug::BACKUP_MM, "Request to deallocate {:p} of size {}", ptr, size);
         self.real_deallocate(ptr, req, align);
         unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).recalculate(); }
     }
@@ -248,6 +502,9 @@ impl BackupAllocator {
         let t = unsafe { self.read_tag((ptr as *mut Tag).offset(-1)).expect("should exist") };
         assert!(t.size() == size || t.size() == size + size_of::<Tag>(), "(t.size() = {}) == (size = {}) failed", t.size(), size);
         t.set_free();
+        let tag_ptr = t.get_tag_ptr();
+        let freed_size = t.size();
+        self.push_free(tag_ptr, freed_size);
     }
 
     fn deallocate_pages(&self, ptr: *mut u8, pgs: usize) {
@@ -255,17 +512,176 @@ impl BackupAllocator {
         self.deallocate_small(ptr, unsafe { page::num_to_addr::<u8>(pgs) as usize });
     }
 
-    /// Returns true if this ptr needs to be deallocated from the backup
+    /// Checks that every byte of `tag`'s payload still holds `fill`, skipping the leading
+    /// intrusive next/prev pointers that the segregated free lists overwrite on a tracked block.
+    fn fill_intact(&self, tag: &Tag, fill: u8) -> bool {
+        let units = tag.size() / size_of::<Tag>();
+        let skip = if units >= MIN_LIST_NODE_UNITS { size_of::<usize>() * 2 } else { 0 };
+        let start = tag.get_start();
+        for i in skip..tag.size() {
+            if unsafe { *start.offset(i as isize) } != fill {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Like `deallocate`, but first checks that the block's tag is marked allocated and that a
+    /// free block directly after it still holds the `FREE_FILL` pattern, so double-free and
+    /// writes into that freed neighbour are caught instead of silently corrupting the heap. The
+    /// block's own `ALOC_FILL` contents are not checked.
+    /// `NotInArena`/`UnalignedPointer` are returned so a caller juggling multiple allocators can
+    /// try the next one; `DoubleFree`/`CorruptedFreeBlock` are genuine invariant violations and
+    /// `kpanic!` immediately, naming which check failed and at what address.
+    pub fn deallocate_checked(&self, ptr: *mut u8, size: usize, align: usize) -> Result<(), CheckMsg> {
+        if (ptr as usize) % size_of::<Tag>() != 0 {
+            return Err(CheckMsg::UnalignedPointer);
+        }
+        if !self.contains_local(ptr) {
+            return if self.next_allocator.is_null() {
+                Err(CheckMsg::NotInArena)
+            } else {
+                unsafe { (*self.next_allocator).deallocate_checked(ptr, size, align) }
+            };
+        }
+        let tag = self.read_tag(unsafe { (ptr as *mut Tag).offset(-1) })
+            .expect("contains_local() already checked ptr is within the arena");
+        if tag.is_free() {
+            dbg!(debug::MM|debug::DANGER, "double free (or free of an interior/unowned pointer) at {:p}", ptr);
+            kpanic!("CheckMsg::{:?} at {:p}", CheckMsg::DoubleFree, ptr);
+        }
+        if let Some(next) = self.read_tag(tag.next()) {
+            if next.is_free() && !self.fill_intact(next, FREE_FILL) {
+                let next_ptr = next.get_tag_ptr();
+                dbg!(debug::MM|debug::DANGER, "free block following {:p} no longer holds FREE_FILL: use-after-free?", ptr);
+                kpanic!("CheckMsg::{:?} at {:p}", CheckMsg::CorruptedFreeBlock, next_ptr);
+            }
+        }
+        self.deallocate(ptr, size, align);
+        Ok(())
+    }
+
+    /// Attempts to grow or shrink a live block without moving it, falling back to an
+    /// allocate+copy+free when the in-place resize isn't possible. Growing absorbs a free tag
+    /// immediately following the block; shrinking splits the tail off into a new free tag.
+    pub fn reallocate(&self, ptr: *mut u8, old_size: usize, new_size: usize, align: usize) -> *mut u8 {
+        let old_req = (old_size + (size_of::<Tag>() - 1)) & (!(size_of::<Tag>() - 1));
+        let new_req = (new_size + (size_of::<Tag>() - 1)) & (!(size_of::<Tag>() - 1));
+        if let Some(res) = self.real_reallocate(ptr, old_req, new_req) {
+            unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).recalculate(); }
+            dbg!(debug::MM|debug::BACKUP_MM, "resized {:p} from {} to {} bytes in place", ptr, old_size, new_size);
+            res
+        } else {
+            let (res, _) = self.allocate_with_size(new_size, align);
+            if !res.is_null() {
+                unsafe { copy_nonoverlapping(ptr, res, cmp::min(old_size, new_size)); }
+                self.deallocate(ptr, old_size, align);
+            }
+            res
+        }
+    }
+
+    /// Tries to resize the block at `ptr` in place. Only handles sub-page blocks, since
+    /// page-granularity blocks are always handed out whole. Returns `None` if the resize cannot
+    /// be done without moving the block.
+    fn real_reallocate(&self, ptr: *mut u8, old_req: usize, new_req: usize) -> Option<*mut u8> {
+        if old_req >= page::SIZE || new_req >= page::SIZE {
+            return None;
+        }
+        let tag = unsafe { self.read_tag((ptr as *mut Tag).offset(-1)).expect("should exist") };
+        assert!(tag.is_allocated());
+        assert!(tag.size() == old_req || tag.size() == old_req + size_of::<Tag>(),
+                "(t.size() = {}) == (old_req = {}) failed", tag.size(), old_req);
+        let old_total = tag.size();
+        if new_req <= old_total {
+            // Shrink: split the tail off into a new free tag, unless the remainder is too small
+            // to be worth its own tag (same threshold `allocate_small` uses when carving).
+            if old_total - new_req > size_of::<Tag>() {
+                let remaining = old_total - new_req - size_of::<Tag>();
+                tag.set_size(new_req);
+                tag.set_allocated();
+                if let Some(new_tag) = self.read_tag(tag.next()) {
+                    *new_tag = Tag::new(remaining);
+                    let new_ptr = new_tag.get_tag_ptr();
+                    unsafe { write_bytes(new_tag.get_start(), FREE_FILL, remaining); }
+                    self.push_free(new_ptr, remaining);
+                }
+            }
+            Some(ptr)
+        } else {
+            // Grow: only works if the following block is free and large enough (together with
+            // its own tag) to cover the new request.
+            match self.read_tag(tag.next()) {
+                Some(next) if next.is_free() && tag.size() + size_of::<Tag>() + next.size() >= new_req => {
+                    let next_ptr = next.get_tag_ptr();
+                    let next_size = next.size();
+                    self.unlink_free(next_ptr, next_size);
+                    let combined_total = old_total + size_of::<Tag>() + next_size;
+                    tag.set_size(new_req);
+                    tag.set_allocated();
+                    if combined_total - new_req > size_of::<Tag>() {
+                        let remaining = combined_total - new_req - size_of::<Tag>();
+                        if let Some(new_tag) = self.read_tag(tag.next()) {
+                            *new_tag = Tag::new(remaining);
+                            let new_ptr = new_tag.get_tag_ptr();
+                            unsafe { write_bytes(new_tag.get_start(), FREE_FILL, remaining); }
+                            self.push_free(new_ptr, remaining);
+                        }
+                    }
+                    Some(ptr)
+                },
+                _ => None,
+            }
+        }
+    }
+
+    /// Returns true if this ptr needs to be deallocated from the backup. Walks the whole chain,
+    /// since the pointer may have come from any link.
     pub fn contains(&self, ptr: *mut u8) -> bool {
+        self.contains_local(ptr) || (!self.next_allocator.is_null() && unsafe { (*self.next_allocator).contains(ptr) })
+    }
+
+    /// Like `contains`, but only checks this link, not the rest of the chain.
+    fn contains_local(&self, ptr: *mut u8) -> bool {
         let v = ptr as usize;
         self.buf as usize <= v && v < unsafe { self.buf.offset(self.byte_len()) as usize }
     }
 
+    /// Carves a contiguous, page-aligned span of `pages` pages out of the free pool and hands
+    /// back a handle that normal allocation can never touch until `release` gives it back. The
+    /// reserved span stays marked allocated, so `largest_space` already excludes it; `pages` is
+    /// also added to `reserved_pages` so `is_memory_low` can subtract it back out and keep
+    /// reserving memory from skewing the general low-memory signal.
+    pub fn reserve(&self, pages: usize) -> Option<Reservation> {
+        let bytes = unsafe { page::num_to_addr::<u8>(pages) as usize };
+        let ptr = self.allocate(bytes, page::SIZE);
+        if ptr.is_null() {
+            None
+        } else {
+            unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).reserved_pages += pages; }
+            dbg!(debug::MM|debug::BACKUP_MM, "reserved {:p}-{:p} ({} pages) for exclusive use",
+                 ptr, unsafe { ptr.offset(bytes as isize) }, pages);
+            Some(Reservation { ptr : ptr, pages : pages })
+        }
+    }
+
+    /// Returns a reservation's span to the general free pool.
+    pub fn release(&self, res: Reservation) {
+        let bytes = unsafe { page::num_to_addr::<u8>(res.pages) as usize };
+        dbg!(debug::MM|debug::BACKUP_MM, "releasing reservation {:p} ({} pages)", res.ptr, res.pages);
+        unsafe { transmute::<&BackupAllocator, &mut BackupAllocator>(self).reserved_pages -= res.pages; }
+        self.deallocate(res.ptr, bytes, page::SIZE);
+    }
+
     pub fn setup(&mut self) {
         unsafe {
             write_bytes::<u8>(self.buf, 0, page::num_to_addr::<u8>(self.pages as usize) as usize);
             write(self.buf as *mut Tag, Tag::new((self.byte_len() as usize) - size_of::<Tag>()));
         }
+        self.small_free_lists = [0 as *mut Tag; NUM_SMALL_CLASSES];
+        self.reserved_pages = 0;
+        // The initial arena is one large free block that is not pushed onto any size-class list;
+        // it's only reachable through the linear scans until it gets split.
     }
 
     fn byte_len(&self) -> isize { unsafe { page::num_to_addr::<u8>(self.pages as usize) as isize } }
@@ -290,14 +706,29 @@ impl BackupAllocator {
                 Some(cur) => {
                     assert!(cur.size() % size_of::<Tag>() == 0);
                     if prev.is_free() && cur.is_free() {
-                        // Coalesce.
+                        // Coalesce. Both blocks are (if large enough) tracked under their
+                        // pre-merge size classes; unlink them before growing `prev` and relist
+                        // the merged block under its new, larger class.
+                        let prev_ptr = prev.get_tag_ptr();
                         let psize = prev.size();
-                        prev.set_size(psize + cur.size() + size_of::<Tag>());
+                        let csize = cur.size();
+                        self.unlink_free(prev_ptr, psize);
+                        self.unlink_free(cur.get_tag_ptr(), csize);
+                        prev.set_size(psize + csize + size_of::<Tag>());
+                        // `cur`'s old boundary tag now sits inside `prev`'s payload, so the
+                        // whole merged payload needs refilling, not just the grown tail.
+                        unsafe { write_bytes(prev.get_start(), FREE_FILL, prev.size()); }
+                        self.push_free(prev_ptr, prev.size());
                         largest = cmp::max(largest, pg_size(prev.size()) - 1);
-                    } else if cur.is_free() {
-                        largest = cmp::max(largest, pg_size(cur.size()) - 1);
+                        // `cur`'s header now lives inside `prev`'s payload, so keep iterating
+                        // from `prev` (whose `.next()` already accounts for the merged size)
+                        // instead of advancing into the just-overwritten bytes.
+                    } else {
+                        if cur.is_free() {
+                            largest = cmp::max(largest, pg_size(cur.size()) - 1);
+                        }
+                        prev = cur;
                     }
-                    prev = cur;
                 },
                 None => { break 'outer; }
             }
@@ -305,12 +736,29 @@ impl BackupAllocator {
         largest
     }
 
-    /// Recalculate all the information about the backup allocator.
+    /// Recalculate all the information about the backup allocator. Walks the whole chain so that
+    /// `largest_space` reflects the biggest contiguous span available anywhere in it.
     fn recalculate(&mut self) {
         self.largest_space = self.do_recalculate();
+        if !self.next_allocator.is_null() {
+            let next = unsafe { self.next_allocator.as_mut().expect("not null") };
+            next.recalculate();
+            self.largest_space = cmp::max(self.largest_space, next.largest_space);
+        }
         dbg!(debug::MM|debug::BACKUP_MM, "largest space is {}", self.largest_space);
     }
 
+    /// Total pages across every link of the chain, used to weigh `largest_space` against when
+    /// deciding if memory is low.
+    fn total_pages(&self) -> usize {
+        self.pages + if self.next_allocator.is_null() { 0 } else { unsafe { (*self.next_allocator).total_pages() } }
+    }
+
+    /// Pages currently pinned by `reserve` across every link of the chain.
+    fn total_reserved_pages(&self) -> usize {
+        self.reserved_pages + if self.next_allocator.is_null() { 0 } else { unsafe { (*self.next_allocator).total_reserved_pages() } }
+    }
+
     pub fn finish(&mut self) {
         if self.buf == 0 as *mut u8 {
             *self = BackupAllocator::new(DEFAULT_BACKUP_PAGES, DEFAULT_THRESHOLD);
@@ -318,7 +766,10 @@ impl BackupAllocator {
     }
 
     pub fn is_memory_low(&self) -> bool {
-        self.is_used() && self.pages - self.largest_space > self.threshold_pages
+        // Reserved pages are pinned allocated, so they'd otherwise inflate total_pages() without
+        // ever showing up in largest_space; subtract them back out so reserving memory doesn't
+        // skew the threshold comparison.
+        self.is_used() && self.total_pages() - self.total_reserved_pages() - self.largest_space > self.threshold_pages
     }
     fn calc_total_space(&self) -> usize {
         let mut tot = 0;